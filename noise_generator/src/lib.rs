@@ -1,3 +1,6 @@
+// The synth now has a `Noise` waveform of its own, so this standalone
+// white-noise generator is kept only as a minimal example plugin; it is
+// intentionally left unchanged rather than merged into the synth.
 use nih_plug::prelude::*;
 use std::sync::Arc;
 use rand::random;
@@ -1,15 +1,214 @@
 use nih_plug::prelude::*;
+use rand::random;
 use std::f32::consts;
 use std::sync::Arc;
 
 
+/// The oscillator shapes the synth can produce from a voice's running phase.
+#[derive(Enum, PartialEq, Clone, Copy)]
+enum Waveform {
+    Sine,
+    Saw,
+    Square,
+    Triangle,
+    Noise,
+}
+
+/// The output tap of the multimode state-variable filter.
+#[derive(Enum, PartialEq, Clone, Copy)]
+enum FilterMode {
+    Lowpass,
+    Highpass,
+    Bandpass,
+}
+
+/// PolyBLEP residual used to round off the discontinuities in the saw and
+/// square waves, taming the aliasing those harmonically rich shapes would
+/// otherwise produce. `t` is the phase in `[0, 1)` and `dt` the per-sample
+/// phase increment.
+fn poly_blep(t: f32, dt: f32) -> f32 {
+    if t < dt {
+        let t = t / dt;
+        2.0 * t - t * t - 1.0
+    } else if t > 1.0 - dt {
+        let t = (t - 1.0) / dt;
+        t * t + 2.0 * t + 1.0
+    } else {
+        0.0
+    }
+}
+
+
+/// The number of simultaneously sounding voices. A fresh `NoteOn` that arrives
+/// while all of these are busy steals one of them.
+const NUM_VOICES: usize = 16;
+
+/// The largest unison stack a single voice can render.
+const MAX_UNISON: usize = 8;
+
+/// Sample one oscillator shape from a running phase in `[0, 1)`. `phase_delta`
+/// is the per-sample increment, needed by the PolyBLEP anti-aliasing.
+fn oscillator(waveform: Waveform, phase: f32, phase_delta: f32) -> f32 {
+    match waveform {
+        Waveform::Sine => (phase * consts::TAU).sin(),
+        Waveform::Saw => (2.0 * phase - 1.0) - poly_blep(phase, phase_delta),
+        Waveform::Square => {
+            let naive = if phase < 0.5 { 1.0 } else { -1.0 };
+            naive + poly_blep(phase, phase_delta) - poly_blep((phase + 0.5) % 1.0, phase_delta)
+        }
+        Waveform::Triangle => 4.0 * (phase - 0.5).abs() - 1.0,
+        Waveform::Noise => random::<f32>() * 2.0 - 1.0,
+    }
+}
+
+/// The stage a voice's amplitude envelope is currently in. A voice is free once
+/// its release has decayed all the way back to silence.
+#[derive(Clone, Copy, PartialEq)]
+enum EnvStage {
+    Attack,
+    Decay,
+    Sustain,
+    Release,
+}
+
+/// A single sounding voice. Each note that is playing owns one of these so that
+/// overlapping notes no longer clobber one another's oscillator state.
+struct Voice {
+    /// Running phase in `[0, 1)` for each unison copy. Each is seeded with a
+    /// random value on note-on so the stack doesn't comb-filter on attack.
+    unison_phases: [f32; MAX_UNISON],
+    /// The MIDI note this voice is rendering.
+    note: u8,
+    /// The note-on velocity, replaced by the note's poly pressure whenever a
+    /// `PolyPressure` event arrives for it. Used to scale the voice's output
+    /// level.
+    velocity: f32,
+    /// The current ADSR stage.
+    stage: EnvStage,
+    /// The current envelope level in `[0, 1]`.
+    env: f32,
+    /// The envelope level captured at the moment the voice entered `Release`,
+    /// so the release ramp is derived from where the envelope actually was
+    /// rather than from the `sustain` parameter.
+    release_level: f32,
+    /// Whether this voice is currently in use.
+    active: bool,
+    /// Monotonic allocation counter, used to steal the oldest voice as a
+    /// fallback.
+    age: u64,
+}
+
+impl Default for Voice {
+    fn default() -> Self {
+        Self {
+            unison_phases: [0.0; MAX_UNISON],
+            note: 0,
+            velocity: 0.0,
+            stage: EnvStage::Release,
+            env: 0.0,
+            release_level: 0.0,
+            active: false,
+            age: 0,
+        }
+    }
+}
+
+impl Voice {
+    /// Advance the per-sample ADSR state machine and return the new envelope
+    /// level. Each stage rate is clamped so that a zero-length time falls back
+    /// to a single-sample transition rather than producing a NaN/inf.
+    fn next_env(&mut self, attack: f32, decay: f32, sustain: f32, release: f32, sample_rate: f32) -> f32 {
+        match self.stage {
+            EnvStage::Attack => {
+                self.env += 1.0 / (attack * sample_rate).max(1.0);
+                if self.env >= 1.0 {
+                    self.env = 1.0;
+                    self.stage = EnvStage::Decay;
+                }
+            }
+            EnvStage::Decay => {
+                self.env -= (1.0 - sustain) / (decay * sample_rate).max(1.0);
+                if self.env <= sustain {
+                    self.env = sustain;
+                    self.stage = EnvStage::Sustain;
+                }
+            }
+            EnvStage::Sustain => {
+                self.env = sustain;
+            }
+            EnvStage::Release => {
+                self.env -= self.release_level.max(f32::EPSILON) / (release * sample_rate).max(1.0);
+                if self.env <= 0.0 {
+                    self.env = 0.0;
+                    self.active = false;
+                }
+            }
+        }
+
+        self.env
+    }
+
+    /// Advance the unison stack by one sample and return its stereo output,
+    /// gated by the current envelope level and the voice's `velocity`. The copies
+    /// are detuned symmetrically around the played note, panned across the
+    /// field when `stereo` is set, and normalized by `1/sqrt(n)` so the level
+    /// stays roughly constant as voices are added.
+    fn next_sample(
+        &mut self,
+        env: f32,
+        waveform: Waveform,
+        unison: usize,
+        detune_cents: f32,
+        stereo: bool,
+        sample_rate: f32,
+    ) -> (f32, f32) {
+        let base = util::midi_note_to_freq(self.note);
+        let norm = (env * self.velocity) / (unison as f32).sqrt();
+
+        let (mut left, mut right) = (0.0, 0.0);
+        for i in 0..unison {
+            // Symmetric detune/pan position in [-1, 1] (0 for a single copy).
+            let spread = if unison > 1 {
+                (i as f32 / (unison as f32 - 1.0)) * 2.0 - 1.0
+            } else {
+                0.0
+            };
+
+            let frequency = base * 2f32.powf((spread * detune_cents) / 1200.0);
+            let phase_delta = frequency / sample_rate;
+            let value = oscillator(waveform, self.unison_phases[i], phase_delta) * norm;
+
+            self.unison_phases[i] += phase_delta;
+            if self.unison_phases[i] >= 1.0 {
+                self.unison_phases[i] -= 1.0;
+            }
+
+            if stereo {
+                // Equal-power pan from leftmost (spread = -1) to rightmost.
+                let angle = (spread + 1.0) * 0.25 * consts::PI;
+                left += value * angle.cos();
+                right += value * angle.sin();
+            } else {
+                left += value;
+                right += value;
+            }
+        }
+
+        (left, right)
+    }
+}
+
 struct DevFestSynth {
     params: Arc<DevFestSynthParams>,
     sample_rate: f32,
     phase: f32,
-    midi_note_id: u8,
-    midi_note_freq: f32,
-    midi_note_gain: Smoother<f32>,
+    voices: Vec<Voice>,
+    /// Incremented on every allocation so voice stealing can find the oldest.
+    next_age: u64,
+    /// Per-channel lowpass/band state of the post-mix Chamberlin filter,
+    /// carried across buffers.
+    filter_low: [f32; 2],
+    filter_band: [f32; 2],
 }
 
 #[derive(Params)]
@@ -20,6 +219,26 @@ struct DevFestSynthParams {
     pub frequency: FloatParam,
     #[id = "usemid"]
     pub use_midi: BoolParam,
+    #[id = "wavefm"]
+    pub waveform: EnumParam<Waveform>,
+    #[id = "attack"]
+    pub attack: FloatParam,
+    #[id = "decay"]
+    pub decay: FloatParam,
+    #[id = "sustain"]
+    pub sustain: FloatParam,
+    #[id = "release"]
+    pub release: FloatParam,
+    #[id = "cutoff"]
+    pub cutoff: FloatParam,
+    #[id = "resnce"]
+    pub resonance: FloatParam,
+    #[id = "fltmod"]
+    pub filter_mode: EnumParam<FilterMode>,
+    #[id = "unison"]
+    pub unison_voices: IntParam,
+    #[id = "detune"]
+    pub detune_cents: FloatParam,
 }
 
 impl Default for DevFestSynth {
@@ -30,9 +249,10 @@ impl Default for DevFestSynth {
 
             phase: 0.0,
 
-            midi_note_id: 0,
-            midi_note_freq: 1.0,
-            midi_note_gain: Smoother::new(SmoothingStyle::Linear(5.0)),
+            voices: (0..NUM_VOICES).map(|_| Voice::default()).collect(),
+            next_age: 0,
+            filter_low: [0.0; 2],
+            filter_band: [0.0; 2],
         }
     }
 }
@@ -64,20 +284,135 @@ impl Default for DevFestSynthParams {
                 .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
                 .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
             use_midi: BoolParam::new("Use MIDI", true),
+            waveform: EnumParam::new("Waveform", Waveform::Sine),
+            attack: FloatParam::new(
+                "Attack",
+                0.01,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 10.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit(" s"),
+            decay: FloatParam::new(
+                "Decay",
+                0.1,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 10.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+                .with_unit(" s"),
+            sustain: FloatParam::new(
+                "Sustain",
+                0.8,
+                FloatRange::Linear { min: 0.0, max: 1.0 },
+            ),
+            release: FloatParam::new(
+                "Release",
+                0.2,
+                FloatRange::Skewed {
+                    min: 0.0,
+                    max: 10.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_unit(" s"),
+            cutoff: FloatParam::new(
+                "Cutoff",
+                5_000.0,
+                FloatRange::Skewed {
+                    min: 20.0,
+                    max: 20_000.0,
+                    factor: FloatRange::skew_factor(-2.0),
+                },
+            )
+                .with_smoother(SmoothingStyle::Linear(10.0))
+                .with_value_to_string(formatters::v2s_f32_hz_then_khz(0))
+                .with_string_to_value(formatters::s2v_f32_hz_then_khz()),
+            resonance: FloatParam::new(
+                "Resonance",
+                0.707,
+                FloatRange::Linear { min: 0.1, max: 10.0 },
+            )
+                .with_smoother(SmoothingStyle::Linear(10.0)),
+            filter_mode: EnumParam::new("Filter Mode", FilterMode::Lowpass),
+            unison_voices: IntParam::new(
+                "Unison Voices",
+                1,
+                IntRange::Linear { min: 1, max: MAX_UNISON as i32 },
+            ),
+            detune_cents: FloatParam::new(
+                "Detune",
+                15.0,
+                FloatRange::Linear { min: 0.0, max: 100.0 },
+            )
+                .with_unit(" ct"),
         }
     }
 }
 
 impl DevFestSynth {
-    fn calculate_sin(&mut self, frequency: f32) -> f32 {
+    fn calculate_wave(&mut self, frequency: f32, waveform: Waveform) -> f32 {
         let phase_delta = frequency / self.sample_rate;
-        let sine = (self.phase * consts::TAU).sin();
+        let value = oscillator(waveform, self.phase, phase_delta);
 
         self.phase += phase_delta;
         if self.phase >= 1.0 {
             self.phase -= 1.0;
         }
-        return sine;
+        return value;
+    }
+
+    /// Run one sample through the Chamberlin state-variable filter and return
+    /// the tap selected by `mode`. `f` is clamped to stay stable when the
+    /// cutoff approaches the Nyquist frequency.
+    fn process_filter(
+        &mut self,
+        channel: usize,
+        input: f32,
+        cutoff: f32,
+        resonance: f32,
+        mode: FilterMode,
+    ) -> f32 {
+        let f = (2.0 * (consts::PI * cutoff / self.sample_rate).sin()).min(0.99);
+        let q = 1.0 / resonance;
+
+        self.filter_low[channel] += f * self.filter_band[channel];
+        let high = input - self.filter_low[channel] - q * self.filter_band[channel];
+        self.filter_band[channel] += f * high;
+
+        match mode {
+            FilterMode::Lowpass => self.filter_low[channel],
+            FilterMode::Highpass => high,
+            FilterMode::Bandpass => self.filter_band[channel],
+        }
+    }
+
+    /// Pick a voice for a new note: a free one if available, otherwise steal the
+    /// voice whose envelope is closest to silence, falling back to the oldest.
+    fn allocate_voice(&mut self) -> &mut Voice {
+        if let Some(idx) = self.voices.iter().position(|voice| !voice.active) {
+            return &mut self.voices[idx];
+        }
+
+        let idx = self
+            .voices
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                a.env
+                    .partial_cmp(&b.env)
+                    .unwrap_or(std::cmp::Ordering::Equal)
+                    .then(a.age.cmp(&b.age))
+            })
+            .map(|(idx, _)| idx)
+            .unwrap();
+        &mut self.voices[idx]
     }
 }
 
@@ -100,7 +435,7 @@ impl Plugin for DevFestSynth {
         },
     ];
 
-    const MIDI_INPUT: MidiConfig = MidiConfig::Basic;
+    const MIDI_INPUT: MidiConfig = MidiConfig::MidiCCs;
     const SAMPLE_ACCURATE_AUTOMATION: bool = true;
 
     type SysExMessage = ();
@@ -123,9 +458,15 @@ impl Plugin for DevFestSynth {
 
     fn reset(&mut self) {
         self.phase = 0.0;
-        self.midi_note_id = 0;
-        self.midi_note_freq = 1.0;
-        self.midi_note_gain.reset(0.0);
+        self.next_age = 0;
+        self.filter_low = [0.0; 2];
+        self.filter_band = [0.0; 2];
+        for voice in self.voices.iter_mut() {
+            voice.active = false;
+            voice.stage = EnvStage::Release;
+            voice.env = 0.0;
+            voice.unison_phases = [0.0; MAX_UNISON];
+        }
     }
 
     fn process(
@@ -138,42 +479,114 @@ impl Plugin for DevFestSynth {
         for (sample_id, channel_samples) in buffer.iter_samples().enumerate() {
             let gain = self.params.gain.smoothed.next();
 
-            let sin = if self.params.use_midi.value() {
-                // Act on the next MIDI event
-                while let Some(event) = next_event {
-                    if event.timing() > sample_id as u32 {
-                        break;
-                    }
+            let stereo = channel_samples.len() >= 2;
 
-                    match event {
-                        NoteEvent::NoteOn { note, velocity, .. } => {
-                            self.midi_note_id = note;
-                            self.midi_note_freq = util::midi_note_to_freq(note);
-                            self.midi_note_gain.set_target(self.sample_rate, velocity);
-                        }
-                        NoteEvent::NoteOff { note, .. } if note == self.midi_note_id => {
-                            self.midi_note_gain.set_target(self.sample_rate, 0.0);
+            // Drain MIDI for this sample regardless of the "Use MIDI" toggle, so
+            // CC automation for gain/cutoff/resonance/attack/release keeps
+            // working even on the test-tone path.
+            while let Some(event) = next_event {
+                if event.timing() > sample_id as u32 {
+                    break;
+                }
+
+                match event {
+                    NoteEvent::NoteOn { note, velocity, .. } => {
+                        let age = self.next_age;
+                        self.next_age += 1;
+
+                        let voice = self.allocate_voice();
+                        voice.note = note;
+                        voice.velocity = velocity;
+                        for phase in voice.unison_phases.iter_mut() {
+                            *phase = random::<f32>();
                         }
-                        NoteEvent::PolyPressure { note, pressure, .. }
-                        if note == self.midi_note_id =>
+                        voice.active = true;
+                        voice.stage = EnvStage::Attack;
+                        voice.age = age;
+                    }
+                    NoteEvent::NoteOff { note, .. } => {
+                        for voice in self.voices.iter_mut() {
+                            if voice.active
+                                && voice.stage != EnvStage::Release
+                                && voice.note == note
                             {
-                                self.midi_note_gain.set_target(self.sample_rate, pressure);
+                                voice.release_level = voice.env;
+                                voice.stage = EnvStage::Release;
+                            }
+                        }
+                    }
+                    NoteEvent::PolyPressure { note, pressure, .. } => {
+                        for voice in self.voices.iter_mut() {
+                            if voice.active && voice.note == note {
+                                voice.velocity = pressure;
                             }
-                        _ => (),
+                        }
                     }
-
-                    next_event = context.next_event();
+                    // Standard General-MIDI controller assignments, mapped
+                    // onto each target parameter's range and fed through its
+                    // smoother to avoid zipper noise.
+                    NoteEvent::MidiCC { cc, value, .. } => {
+                        let sample_rate = self.sample_rate;
+                        let param = match cc {
+                            7 => Some(&self.params.gain),       // Channel Volume
+                            74 => Some(&self.params.cutoff),     // Brightness
+                            71 => Some(&self.params.resonance),  // Harmonic Content
+                            73 => Some(&self.params.attack),     // Attack Time
+                            72 => Some(&self.params.release),    // Release Time
+                            _ => None,
+                        };
+                        if let Some(param) = param {
+                            param
+                                .smoothed
+                                .set_target(sample_rate, param.preview_plain(value));
+                        }
+                    }
+                    _ => (),
                 }
 
-                // This gain envelope prevents clicks with new notes and with released notes
-                self.calculate_sin(self.midi_note_freq) * self.midi_note_gain.next()
+                next_event = context.next_event();
+            }
+
+            let (left, right) = if self.params.use_midi.value() {
+                // Sum every active voice. `next_env` frees a voice once its
+                // release stage has decayed to silence.
+                let attack = self.params.attack.smoothed.next();
+                let decay = self.params.decay.value();
+                let sustain = self.params.sustain.value();
+                let release = self.params.release.smoothed.next();
+                let waveform = self.params.waveform.value();
+                let unison = self.params.unison_voices.value() as usize;
+                let detune = self.params.detune_cents.value();
+
+                let (mut left, mut right) = (0.0, 0.0);
+                for voice in self.voices.iter_mut() {
+                    if !voice.active {
+                        continue;
+                    }
+
+                    let env = voice.next_env(attack, decay, sustain, release, self.sample_rate);
+                    let (l, r) =
+                        voice.next_sample(env, waveform, unison, detune, stereo, self.sample_rate);
+                    left += l;
+                    right += r;
+                }
+                (left, right)
             } else {
                 let frequency = self.params.frequency.smoothed.next();
-                self.calculate_sin(frequency)
+                let value = self.calculate_wave(frequency, self.params.waveform.value());
+                (value, value)
             };
 
-            for sample in channel_samples {
-                *sample = sin * util::db_to_gain_fast(gain);
+            // Shape the mixed signal with the post-mix state-variable filter
+            let cutoff = self.params.cutoff.smoothed.next();
+            let resonance = self.params.resonance.smoothed.next();
+            let filter_mode = self.params.filter_mode.value();
+            let left = self.process_filter(0, left, cutoff, resonance, filter_mode);
+            let right = self.process_filter(1, right, cutoff, resonance, filter_mode);
+
+            for (channel, sample) in channel_samples.into_iter().enumerate() {
+                let value = if channel == 0 { left } else { right };
+                *sample = value * util::db_to_gain_fast(gain);
             }
         }
 
@@ -206,4 +619,4 @@ impl Vst3Plugin for DevFestSynth {
 }
 
 nih_export_clap!(DevFestSynth);
-nih_export_vst3!(DevFestSynth);
\ No newline at end of file
+nih_export_vst3!(DevFestSynth);